@@ -1,11 +1,14 @@
 #![deny(clippy::all)]
 
 use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{
+    ErrorStrategy, ThreadsafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
 use napi_derive::napi;
 use serde::Deserialize;
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
 use std::time::Duration;
 use tao::event::{Event, WindowEvent};
 use tao::event_loop::{ControlFlow, EventLoop};
@@ -25,13 +28,26 @@ struct RenderState {
     #[allow(dead_code)] // Must keep window alive for the duration of the render
     window: Window,
     webview: Arc<Mutex<WebView>>,
-    html_result: Arc<Mutex<Option<String>>>,
-    result_tx: mpsc::Sender<std::result::Result<String, RenderError>>,
+    outcome: Arc<Mutex<Option<std::result::Result<RenderOutcome, RenderError>>>>,
+    result_tx: mpsc::Sender<std::result::Result<RenderOutcome, RenderError>>,
     start_time: std::time::Instant,
     timeout_duration: Duration,
+    on_event: Option<ThreadsafeFunction<String, ErrorStrategy::Fatal>>,
+    pending_events: Arc<Mutex<Vec<String>>>,
 }
 
-#[derive(Deserialize, Default)]
+/// The extracted HTML plus the JSON-encoded return value of `RenderOptions.script`
+/// (present only when `RenderOptions.return_script` is set). Posted from the page as a
+/// single tagged `result` IPC frame once `checkAndExtract` finishes.
+#[derive(Debug, Clone)]
+struct RenderOutcome {
+    html: String,
+    script_result: Option<String>,
+}
+
+// `JsFunction` implements neither `Deserialize` nor `Clone`, so this struct can't derive
+// either; `ResolvedRenderOptions::from_options` is what downstream code clones instead.
+#[derive(Default)]
 #[napi(object)]
 pub struct RenderOptions {
     /// Maximum time to wait for rendering in milliseconds
@@ -45,6 +61,111 @@ pub struct RenderOptions {
 
     /// JavaScript code to execute before capturing HTML
     pub script: Option<String>,
+
+    /// Capture `script`'s completion value (JSON-encoded) as `script_result`. Defaults to
+    /// `false`, discarding the value as before.
+    pub return_script: Option<bool>,
+
+    /// Extra origins, in addition to `url`'s own origin, that the top-level frame may be
+    /// on while IPC frames are accepted. Only the top frame's tracked origin is checked,
+    /// not the origin of the frame that posts a message; on Linux that tracked origin
+    /// stays at `url`'s origin, so extra entries have no effect there.
+    pub allowed_origins: Option<Vec<String>>,
+
+    /// Called with a JSON-encoded `{type, ...}` event for console output, page errors,
+    /// and navigation, in the order they occurred.
+    pub on_event: Option<JsFunction>,
+
+    /// Readiness gates beyond `wait_for`'s CSS selector check; all must hold before
+    /// extraction proceeds.
+    pub wait_until: Option<WaitUntil>,
+}
+
+/// `predicate`: a JS function body polled each tick; extraction waits until it returns
+/// truthy. Unlike `script`, whose completion value is used, it is run via
+/// `new Function`, so it must `return` its value explicitly. `network_idle_ms`: extraction also waits until no
+/// `fetch`/`XMLHttpRequest` call has been outstanding for this many milliseconds, for
+/// client-rendered pages whose content arrives via async requests issued after `load`.
+#[derive(Default, Clone)]
+#[napi(object)]
+pub struct WaitUntil {
+    pub predicate: Option<String>,
+    pub network_idle_ms: Option<i64>,
+}
+
+/// `RenderOptions` with `on_event` converted to a `ThreadsafeFunction`. `JsFunction` is
+/// neither `Clone` nor `Send`, so this conversion has to happen on the calling thread,
+/// right after a `RenderOptions` comes in from JS and before it's cloned for a batch's
+/// default options or handed to another thread.
+#[derive(Clone, Default)]
+struct ResolvedRenderOptions {
+    timeout: Option<i64>,
+    wait_for: Option<String>,
+    selector: Option<String>,
+    script: Option<String>,
+    return_script: Option<bool>,
+    allowed_origins: Option<Vec<String>>,
+    on_event: Option<ThreadsafeFunction<String, ErrorStrategy::Fatal>>,
+    wait_until: Option<WaitUntil>,
+}
+
+impl ResolvedRenderOptions {
+    fn from_options(opts: RenderOptions) -> Result<Self> {
+        let on_event = opts
+            .on_event
+            .map(|callback| {
+                callback.create_threadsafe_function(0, |ctx: ThreadsafeCallContext<String>| {
+                    ctx.env.create_string(&ctx.value).map(|s| vec![s])
+                })
+            })
+            .transpose()?;
+
+        Ok(ResolvedRenderOptions {
+            timeout: opts.timeout,
+            wait_for: opts.wait_for,
+            selector: opts.selector,
+            script: opts.script,
+            return_script: opts.return_script,
+            allowed_origins: opts.allowed_origins,
+            on_event,
+            wait_until: opts.wait_until,
+        })
+    }
+}
+
+/// A single URL plus its own `RenderOptions` for use with `render_pages_with_options`.
+/// When `options` is `None`, the batch's `default_options` are used instead.
+#[napi(object)]
+pub struct UrlRenderRequest {
+    pub url: String,
+    pub options: Option<RenderOptions>,
+}
+
+/// Combined result of a render: the extracted HTML plus the JSON-encoded return value of
+/// `RenderOptions.script` (see `RenderOptions.return_script`).
+#[napi(object)]
+pub struct RenderResult {
+    pub html: String,
+    pub script_result: Option<String>,
+}
+
+impl From<RenderOutcome> for RenderResult {
+    fn from(outcome: RenderOutcome) -> Self {
+        RenderResult {
+            html: outcome.html,
+            script_result: outcome.script_result,
+        }
+    }
+}
+
+/// Outcome of rendering one page within a batch. Exactly one of `html`/`error` is set;
+/// batches keep going even if individual pages fail, so failures surface per-item
+/// instead of rejecting the whole batch.
+#[napi(object)]
+pub struct PageRenderResult {
+    pub html: Option<String>,
+    pub script_result: Option<String>,
+    pub error: Option<String>,
 }
 
 #[derive(Debug)]
@@ -56,28 +177,29 @@ pub enum RenderError {
     Unknown(String),
 }
 
+impl RenderError {
+    fn message(&self) -> String {
+        match self {
+            RenderError::WindowCreation(msg) => format!("WindowCreationError: {}", msg),
+            RenderError::WebViewCreation(msg) => format!("WebViewCreationError: {}", msg),
+            RenderError::Timeout => "RenderTimeoutError: Rendering timed out".to_string(),
+            RenderError::ScriptExecution(msg) => format!("ScriptError: {}", msg),
+            RenderError::Unknown(msg) => format!("UnknownError: {}", msg),
+        }
+    }
+}
+
 impl From<RenderError> for napi::Error {
     fn from(err: RenderError) -> Self {
-        match err {
-            RenderError::WindowCreation(msg) => {
-                napi::Error::from_reason(format!("WindowCreationError: {}", msg))
-            }
-            RenderError::WebViewCreation(msg) => {
-                napi::Error::from_reason(format!("WebViewCreationError: {}", msg))
-            }
-            RenderError::Timeout => napi::Error::from_reason("RenderTimeoutError: Rendering timed out"),
-            RenderError::ScriptExecution(msg) => {
-                napi::Error::from_reason(format!("ScriptError: {}", msg))
-            }
-            RenderError::Unknown(msg) => napi::Error::from_reason(format!("UnknownError: {}", msg)),
-        }
+        napi::Error::from_reason(err.message())
     }
 }
 
-/// Renders a webpage using a native WebView and returns the final HTML
+/// Renders a webpage using a native WebView and returns the final HTML (plus the
+/// script's return value when `RenderOptions.return_script` is set)
 #[napi]
-pub fn render_page(url: String, options: Option<RenderOptions>) -> Result<String> {
-    let opts = options.unwrap_or_default();
+pub fn render_page(url: String, options: Option<RenderOptions>) -> Result<RenderResult> {
+    let opts = ResolvedRenderOptions::from_options(options.unwrap_or_default())?;
     let timeout_ms = opts.timeout.unwrap_or(5000);
 
     EVENT_LOOP.with(|event_loop_cell| {
@@ -96,7 +218,7 @@ pub fn render_page(url: String, options: Option<RenderOptions>) -> Result<String
             .map_err(|e| -> napi::Error { e.into() })?;
 
         // Run the event loop until this render completes
-        run_event_loop(event_loop, window_id);
+        run_event_loop(event_loop, &[window_id]);
 
         // Get the result
         result_rx
@@ -104,16 +226,302 @@ pub fn render_page(url: String, options: Option<RenderOptions>) -> Result<String
             .map_err(|_| {
                 napi::Error::from_reason("Failed to receive result from event loop".to_string())
             })?
+            .map(RenderResult::from)
             .map_err(|e: RenderError| -> napi::Error { e.into() })
     })
 }
 
+/// Like `render_page`, but never blocks the calling thread: pumps the same main-thread
+/// `EVENT_LOOP` (tao requires it stay there) in short `WaitUntil` bursts instead of
+/// running it to completion up front.
+#[napi]
+pub fn render_page_async(env: Env, url: String, options: Option<RenderOptions>) -> Result<JsObject> {
+    let (deferred, promise) = env.create_deferred()?;
+    let opts = ResolvedRenderOptions::from_options(options.unwrap_or_default())?;
+    let timeout_ms = opts.timeout.unwrap_or(5000);
+
+    let (window_id, result_rx) = EVENT_LOOP
+        .with(|event_loop_cell| {
+            let mut event_loop_opt = event_loop_cell.borrow_mut();
+            if event_loop_opt.is_none() {
+                *event_loop_opt = Some(EventLoop::new());
+            }
+            let event_loop = event_loop_opt.as_mut().unwrap();
+            setup_render(event_loop, &url, opts, timeout_ms)
+        })
+        .map_err(|e| -> napi::Error { e.into() })?;
+
+    spawn_async_pump(env, deferred, window_id, result_rx)?;
+
+    Ok(promise)
+}
+
+/// One `render_page_async` call still waiting on a result: its window, its private
+/// result channel, and the promise to settle once that channel produces something.
+struct PendingAsyncRender {
+    window_id: WindowId,
+    result_rx: mpsc::Receiver<std::result::Result<RenderOutcome, RenderError>>,
+    deferred: JsDeferred<RenderResult>,
+}
+
+#[derive(Default)]
+struct AsyncPumpState {
+    pending: Vec<PendingAsyncRender>,
+    thread_running: bool,
+}
+
+// Every concurrent `render_page_async` call shares this one pump thread instead of
+// spawning its own; see `spawn_async_pump`.
+static ASYNC_PUMP: OnceLock<Mutex<AsyncPumpState>> = OnceLock::new();
+
+fn async_pump_state() -> &'static Mutex<AsyncPumpState> {
+    ASYNC_PUMP.get_or_init(|| Mutex::new(AsyncPumpState::default()))
+}
+
+/// Registers `render_page_async`'s pending result and makes sure exactly one background
+/// pump thread is running to drive it. A call made while a pump thread is already alive
+/// just joins its pending list; otherwise it starts a new one, which exits once its
+/// pending list drains empty. Without this, every concurrent `render_page_async` call
+/// paid for its own OS thread and its own `ThreadsafeFunction`, all re-pumping
+/// `EVENT_LOOP` independently ~125 times a second each.
+fn spawn_async_pump(
+    env: Env,
+    deferred: JsDeferred<RenderResult>,
+    window_id: WindowId,
+    result_rx: mpsc::Receiver<std::result::Result<RenderOutcome, RenderError>>,
+) -> Result<()> {
+    let mut state = async_pump_state().lock().unwrap();
+    state.pending.push(PendingAsyncRender {
+        window_id,
+        result_rx,
+        deferred,
+    });
+    if state.thread_running {
+        return Ok(());
+    }
+    state.thread_running = true;
+    drop(state);
+
+    let noop = env.create_function_from_closure("fetchWithRenderPumpTick", |ctx| ctx.env.get_undefined())?;
+    let tick_fn: ThreadsafeFunction<(), ErrorStrategy::Fatal> =
+        noop.create_threadsafe_function(0, |ctx: ThreadsafeCallContext<()>| {
+            let window_ids: Vec<WindowId> = async_pump_state()
+                .lock()
+                .unwrap()
+                .pending
+                .iter()
+                .map(|pending| pending.window_id)
+                .collect();
+            EVENT_LOOP.with(|event_loop_cell| {
+                if let Some(event_loop) = event_loop_cell.borrow_mut().as_mut() {
+                    pump_event_loop_once(event_loop, &window_ids);
+                }
+            });
+            ctx.env.get_undefined().map(|v| vec![v])
+        })?;
+
+    std::thread::spawn(move || loop {
+        tick_fn.call((), ThreadsafeFunctionCallMode::NonBlocking);
+        std::thread::sleep(EVENT_LOOP_TICK);
+
+        let mut state = async_pump_state().lock().unwrap();
+        let mut i = 0;
+        while i < state.pending.len() {
+            match state.pending[i].result_rx.try_recv() {
+                Ok(Ok(outcome)) => {
+                    let pending = state.pending.remove(i);
+                    pending
+                        .deferred
+                        .resolve(move |_env| Ok(RenderResult::from(outcome)));
+                }
+                Ok(Err(e)) => {
+                    let pending = state.pending.remove(i);
+                    pending.deferred.reject(e.into());
+                }
+                Err(mpsc::TryRecvError::Empty) => i += 1,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    let pending = state.pending.remove(i);
+                    pending.deferred.reject(napi::Error::from_reason(
+                        "Failed to receive result from event loop".to_string(),
+                    ));
+                }
+            }
+        }
+
+        if state.pending.is_empty() {
+            state.thread_running = false;
+            break;
+        }
+    });
+
+    Ok(())
+}
+
+/// Renders many URLs over a single shared event loop instead of paying the cost of
+/// `EventLoop::run_return` once per page. Results are returned in the same order as
+/// `urls`. See `render_pages_with_options` for per-URL options and the `max_parallel`
+/// wave size.
+#[napi]
+pub fn render_pages(
+    urls: Vec<String>,
+    options: Option<RenderOptions>,
+    max_parallel: Option<u32>,
+) -> Result<Vec<PageRenderResult>> {
+    let requests = urls
+        .into_iter()
+        .map(|url| UrlRenderRequest { url, options: None })
+        .collect();
+    render_pages_with_options(requests, options, max_parallel)
+}
+
+/// Like `render_pages`, but each URL may carry its own `RenderOptions`, falling back to
+/// `default_options` when a request doesn't set any. All windows/webviews in a wave are
+/// created up front and driven by one `run_event_loop` pass; `max_parallel` caps how many
+/// renders are in flight at once, processing larger batches in waves rather than spinning
+/// up every window simultaneously.
+#[napi]
+pub fn render_pages_with_options(
+    requests: Vec<UrlRenderRequest>,
+    default_options: Option<RenderOptions>,
+    max_parallel: Option<u32>,
+) -> Result<Vec<PageRenderResult>> {
+    let default_opts = ResolvedRenderOptions::from_options(default_options.unwrap_or_default())?;
+    let wave_size = max_parallel
+        .map(|n| n as usize)
+        .filter(|&n| n > 0)
+        .unwrap_or(requests.len().max(1));
+
+    let mut results: Vec<Option<PageRenderResult>> = (0..requests.len()).map(|_| None).collect();
+    let mut remaining = requests.into_iter().enumerate();
+
+    EVENT_LOOP.with(|event_loop_cell| -> Result<()> {
+        let mut event_loop_opt = event_loop_cell.borrow_mut();
+        if event_loop_opt.is_none() {
+            *event_loop_opt = Some(EventLoop::new());
+        }
+        let event_loop = event_loop_opt.as_mut().unwrap();
+
+        loop {
+            let wave: Vec<(usize, UrlRenderRequest)> = remaining.by_ref().take(wave_size).collect();
+            if wave.is_empty() {
+                break;
+            }
+
+            let mut receivers = Vec::with_capacity(wave.len());
+            let mut wave_window_ids = Vec::with_capacity(wave.len());
+
+            for (idx, req) in wave {
+                let opts = match req.options {
+                    Some(raw) => ResolvedRenderOptions::from_options(raw)?,
+                    None => default_opts.clone(),
+                };
+                let timeout_ms = opts.timeout.unwrap_or(5000);
+
+                match setup_render(event_loop, &req.url, opts, timeout_ms) {
+                    Ok((window_id, rx)) => {
+                        wave_window_ids.push(window_id);
+                        receivers.push((idx, rx));
+                    }
+                    Err(e) => {
+                        results[idx] = Some(PageRenderResult {
+                            html: None,
+                            script_result: None,
+                            error: Some(e.message()),
+                        })
+                    }
+                }
+            }
+
+            // Drive this wave's renders to completion before starting the next.
+            run_event_loop(event_loop, &wave_window_ids);
+
+            for (idx, rx) in receivers {
+                results[idx] = Some(match rx.recv() {
+                    Ok(Ok(outcome)) => PageRenderResult {
+                        html: Some(outcome.html),
+                        script_result: outcome.script_result,
+                        error: None,
+                    },
+                    Ok(Err(e)) => PageRenderResult {
+                        html: None,
+                        script_result: None,
+                        error: Some(e.message()),
+                    },
+                    Err(_) => PageRenderResult {
+                        html: None,
+                        script_result: None,
+                        error: Some("Failed to receive result from event loop".to_string()),
+                    },
+                });
+            }
+        }
+
+        Ok(())
+    })?;
+
+    Ok(results.into_iter().map(|r| r.unwrap()).collect())
+}
+
+/// A tagged IPC frame posted by the page as `window.ipc.postMessage(JSON.stringify(...))`.
+/// Replaces the old `"HTML:" + html` convention so the page can report more than one
+/// kind of event (see `type`) over a single channel.
+#[derive(Deserialize)]
+struct IpcFrame {
+    #[serde(rename = "type")]
+    frame_type: String,
+    payload: serde_json::Value,
+}
+
+/// Extracts the `scheme://host[:port]` origin from a URL, matching the format of JS's
+/// `window.location.origin`. Used to seed the IPC allowlist with the render's own
+/// navigation target without pulling in a full URL-parsing dependency.
+fn origin_of(url: &str) -> Option<String> {
+    let scheme_end = url.find("://")?;
+    let after_scheme = &url[scheme_end + 3..];
+    let authority_end = after_scheme
+        .find(['/', '?', '#'])
+        .unwrap_or(after_scheme.len());
+    Some(format!("{}://{}", &url[..scheme_end], &after_scheme[..authority_end]))
+}
+
+/// Whether `current_origin` -- the top frame's real origin, as tracked by
+/// `with_navigation_handler` -- is in `allowed_origins`. Kept as its own function so the
+/// check can be unit tested without a webview and can never be passed anything the page
+/// itself supplies.
+fn origin_allowed(allowed_origins: &[String], current_origin: &str) -> bool {
+    allowed_origins.iter().any(|origin| origin == current_origin)
+}
+
+/// Builds the `{type, ...}` event relayed to `on_event` for a lifecycle frame, merging
+/// the payload's fields in alongside `type` rather than nesting them.
+fn build_relay_event(frame_type: &str, payload: serde_json::Value) -> serde_json::Value {
+    let mut event = serde_json::Map::new();
+    event.insert(
+        "type".to_string(),
+        serde_json::Value::String(frame_type.to_string()),
+    );
+    match payload {
+        serde_json::Value::Object(fields) => event.extend(fields),
+        serde_json::Value::Null => {}
+        other => {
+            event.insert("payload".to_string(), other);
+        }
+    }
+    serde_json::Value::Object(event)
+}
+
 fn setup_render(
     event_loop: &EventLoop<()>,
     url: &str,
-    opts: RenderOptions,
+    opts: ResolvedRenderOptions,
     timeout_ms: i64,
-) -> std::result::Result<(WindowId, mpsc::Receiver<std::result::Result<String, RenderError>>), RenderError> {
+) -> std::result::Result<
+    (
+        WindowId,
+        mpsc::Receiver<std::result::Result<RenderOutcome, RenderError>>,
+    ),
+    RenderError,
+> {
     let window = WindowBuilder::new()
         .with_visible(false)
         .with_title("fetch-with-render")
@@ -122,49 +530,247 @@ fn setup_render(
 
     let window_id = window.id();
 
-    let html_result: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
-    let html_ipc = Arc::clone(&html_result);
+    let outcome: Arc<Mutex<Option<std::result::Result<RenderOutcome, RenderError>>>> =
+        Arc::new(Mutex::new(None));
+    let outcome_ipc = Arc::clone(&outcome);
 
     let wait_for = opts.wait_for.clone();
     let selector = opts.selector.clone();
     let script = opts.script.clone();
+    let return_script = opts.return_script.unwrap_or(false);
+    let wait_until = opts.wait_until.clone().unwrap_or_default();
+    let wait_predicate = wait_until.predicate.clone();
+    let network_idle_ms = wait_until.network_idle_ms;
+
+    // The render's own origin is always trusted; `allowed_origins` adds further origins
+    // the top-level frame may navigate to. Neither list is checked against the origin of
+    // the frame that posts a message.
+    let mut allowed_origins = opts.allowed_origins.clone().unwrap_or_default();
+    allowed_origins.extend(origin_of(url));
+
+    // Tracks the top-level frame's real current origin, updated by
+    // `with_navigation_handler` (wry itself, not the page) -- unlike a JSON field the
+    // page populates, this can't be spoofed by a cross-origin iframe forging a message.
+    //
+    // Except on Linux: wry's WebKitGTK backend wires `navigation_handler` through
+    // `connect_decide_policy` with no main-frame check, so it fires for iframe
+    // navigations exactly like top-frame ones (macOS's WKWebView backend gates on
+    // `isMainFrame`; Windows' WebView2 backend only subscribes to the top-level
+    // `NavigationStarting` event). Trusting it there would let an embedded cross-origin
+    // iframe navigate itself and relabel the whole render as its own origin, so on
+    // Linux `current_origin` is pinned to `url`'s origin instead of being updated.
+    let current_origin = Arc::new(Mutex::new(origin_of(url).unwrap_or_default()));
+    let current_origin_for_nav = Arc::clone(&current_origin);
+    let current_origin_for_ipc = Arc::clone(&current_origin);
+
+    // Lifecycle events (console/page errors/navigation) queue here and are relayed to
+    // `on_event` by `process_render_states_event` (from `run_event_loop` or
+    // `pump_event_loop_once`), in the order they occurred.
+    let pending_events: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let pending_events_ipc = Arc::clone(&pending_events);
+
+    let on_event = opts.on_event;
 
-    // IPC handler for receiving messages from webview
+    // IPC handler for receiving tagged frames from the webview
     let ipc_handler = move |msg: String| {
-        if msg.starts_with("HTML:") {
-            let html = msg.strip_prefix("HTML:").unwrap_or("");
-            *html_ipc.lock().unwrap() = Some(html.to_string());
+        let frame: IpcFrame = match serde_json::from_str(&msg) {
+            Ok(frame) => frame,
+            Err(_) => return,
+        };
+
+        let current = current_origin_for_ipc.lock().unwrap().clone();
+        if !origin_allowed(&allowed_origins, &current) {
+            // Drop messages unless the engine-tracked current origin is trusted; a
+            // cross-origin iframe can still call `window.ipc.postMessage` directly, but
+            // it can't make the top frame's navigation history say it's somewhere else.
+            return;
+        }
+
+        match frame.frame_type.as_str() {
+            "result" => {
+                let html = frame
+                    .payload
+                    .get("html")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("")
+                    .to_string();
+                let script_result = frame
+                    .payload
+                    .get("scriptResult")
+                    .filter(|v| !v.is_null())
+                    .map(|v| v.to_string());
+                *outcome_ipc.lock().unwrap() = Some(Ok(RenderOutcome { html, script_result }));
+            }
+            "error" => {
+                let message = frame
+                    .payload
+                    .get("message")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("Script execution failed")
+                    .to_string();
+                *outcome_ipc.lock().unwrap() = Some(Err(RenderError::ScriptExecution(message)));
+            }
+            // Anything else is a lifecycle event: relay it to `on_event` as `{type, ...}`.
+            frame_type => {
+                pending_events_ipc
+                    .lock()
+                    .unwrap()
+                    .push(build_relay_event(frame_type, frame.payload).to_string());
+            }
         }
     };
 
     let webview = WebViewBuilder::new(&window)
         .with_url(url)
+        .with_navigation_handler(move |target_url: String| {
+            // See the comment on `current_origin` above: on Linux this fires for iframe
+            // navigations too, so it can't be trusted to update the tracked origin there.
+            if !cfg!(target_os = "linux") {
+                if let Some(origin) = origin_of(&target_url) {
+                    *current_origin_for_nav.lock().unwrap() = origin;
+                }
+            }
+            true
+        })
         .with_initialization_script(&format!(
             r#"
+            // This script runs in every frame the page loads, including cross-origin
+            // iframes; only the top frame drives extraction and IPC.
+            if (window !== window.top) {{
+                // no-op
+            }} else {{
             window.__renderReady = false;
             window.__waitFor = {};
             window.__selector = {};
             window.__customScript = {};
+            window.__returnScript = {};
+            window.__waitUntilPredicate = {};
+            window.__predicateFn = window.__waitUntilPredicate ? new Function(window.__waitUntilPredicate) : null;
+            window.__networkIdleMs = {};
+            window.__fetchInFlight = 0;
+            window.__lastActivity = performance.now();
+            window.__scriptStarted = false;
+            window.__resultPosted = false;
 
-            window.addEventListener('load', () => {{
-                window.__renderReady = true;
-            }});
-
-            window.checkAndExtract = function() {{
-                if (!window.__renderReady) return false;
+            // Patch fetch/XHR to track in-flight requests for the network-idle wait
+            // condition; content that arrives after `load` via async requests would
+            // otherwise be missed by a selector-only gate.
+            if (window.__networkIdleMs !== null) {{
+                const originalFetch = window.fetch;
+                if (originalFetch) {{
+                    window.fetch = function() {{
+                        window.__fetchInFlight++;
+                        window.__lastActivity = performance.now();
+                        return originalFetch.apply(this, arguments).finally(function() {{
+                            window.__fetchInFlight--;
+                            window.__lastActivity = performance.now();
+                        }});
+                    }};
+                }}
 
-                if (window.__waitFor) {{
-                    if (!document.querySelector(window.__waitFor)) return false;
+                const OriginalXHR = window.XMLHttpRequest;
+                if (OriginalXHR) {{
+                    window.XMLHttpRequest = function() {{
+                        const xhr = new OriginalXHR();
+                        xhr.addEventListener('loadstart', function() {{
+                            window.__fetchInFlight++;
+                            window.__lastActivity = performance.now();
+                        }});
+                        xhr.addEventListener('loadend', function() {{
+                            window.__fetchInFlight--;
+                            window.__lastActivity = performance.now();
+                        }});
+                        return xhr;
+                    }};
+                    window.XMLHttpRequest.prototype = OriginalXHR.prototype;
                 }}
+            }}
 
-                if (window.__customScript) {{
+            // All configured readiness gates must hold before `checkAndExtract` proceeds
+            // past `wait_for`'s selector check.
+            window.__conditionsMet = function() {{
+                if (window.__waitFor && !document.querySelector(window.__waitFor)) return false;
+
+                if (window.__predicateFn) {{
                     try {{
-                        eval(window.__customScript);
-                    }} catch(e) {{
-                        console.error('Script error:', e);
+                        if (!window.__predicateFn()) return false;
+                    }} catch (e) {{
+                        return false;
                     }}
                 }}
 
+                if (window.__networkIdleMs !== null) {{
+                    if (window.__fetchInFlight !== 0) return false;
+                    if (performance.now() - window.__lastActivity <= window.__networkIdleMs) return false;
+                }}
+
+                return true;
+            }};
+
+            window.addEventListener('load', () => {{
+                window.__renderReady = true;
+            }});
+
+            window.__postFrame = function(type, payload) {{
+                window.ipc.postMessage(JSON.stringify({{ type: type, payload: payload }}));
+            }};
+
+            // Lifecycle events: forwarded as their own frame types so `on_event` can
+            // observe console output, uncaught errors, and SPA navigation transitions
+            // instead of only ever finding out via a selector timeout.
+            ['log', 'warn', 'error'].forEach(function(level) {{
+                const original = console[level];
+                console[level] = function() {{
+                    const args = Array.prototype.slice.call(arguments).map(function(arg) {{
+                        if (typeof arg === 'string') return arg;
+                        try {{ return JSON.stringify(arg); }} catch (e) {{ return String(arg); }}
+                    }});
+                    window.__postFrame('console', {{ level: level, args: args }});
+                    return original.apply(console, arguments);
+                }};
+            }});
+
+            window.addEventListener('error', function(event) {{
+                window.__postFrame('pageerror', {{
+                    message: event.message,
+                    source: event.filename,
+                    lineno: event.lineno,
+                    colno: event.colno
+                }});
+            }});
+
+            window.addEventListener('unhandledrejection', function(event) {{
+                window.__postFrame('unhandledrejection', {{ reason: String(event.reason) }});
+            }});
+
+            document.addEventListener('DOMContentLoaded', function() {{
+                window.__postFrame('domcontentloaded', {{ url: window.location.href }});
+            }});
+
+            window.addEventListener('load', function() {{
+                window.__postFrame('load', {{ url: window.location.href }});
+            }});
+
+            (function() {{
+                const notifyNavigation = function(kind) {{
+                    window.__postFrame('navigation', {{ kind: kind, url: window.location.href }});
+                }};
+                const pushState = history.pushState;
+                history.pushState = function() {{
+                    const result = pushState.apply(this, arguments);
+                    notifyNavigation('pushState');
+                    return result;
+                }};
+                const replaceState = history.replaceState;
+                history.replaceState = function() {{
+                    const result = replaceState.apply(this, arguments);
+                    notifyNavigation('replaceState');
+                    return result;
+                }};
+                window.addEventListener('popstate', function() {{ notifyNavigation('popstate'); }});
+            }})();
+
+            window.__finishExtract = function(scriptResult) {{
                 let html;
                 if (window.__selector) {{
                     const el = document.querySelector(window.__selector);
@@ -172,14 +778,51 @@ fn setup_render(
                 }} else {{
                     html = document.documentElement.outerHTML;
                 }}
+                window.__postFrame('result', {{
+                    html: html,
+                    scriptResult: window.__returnScript ? scriptResult : null
+                }});
+                window.__resultPosted = true;
+            }};
+
+            // Returns truthy once a result (or error) frame has been posted. When
+            // `__customScript` resolves to a Promise, extraction waits for it to settle
+            // before capturing the HTML.
+            window.checkAndExtract = function() {{
+                if (window.__resultPosted) return true;
+                if (!window.__renderReady) return false;
+                if (!window.__conditionsMet()) return false;
+
+                if (window.__customScript) {{
+                    if (!window.__scriptStarted) {{
+                        window.__scriptStarted = true;
+                        try {{
+                            const value = (function() {{ return eval(window.__customScript); }})();
+                            Promise.resolve(value).then(function(resolved) {{
+                                window.__finishExtract(resolved);
+                            }}).catch(function(err) {{
+                                window.__postFrame('error', {{ message: String(err) }});
+                                window.__resultPosted = true;
+                            }});
+                        }} catch (e) {{
+                            window.__postFrame('error', {{ message: String(e) }});
+                            window.__resultPosted = true;
+                        }}
+                    }}
+                    return window.__resultPosted;
+                }}
 
-                window.ipc.postMessage('HTML:' + html);
+                window.__finishExtract(undefined);
                 return true;
             }};
+            }}
             "#,
             serde_json::to_string(&wait_for).unwrap_or("null".to_string()),
             serde_json::to_string(&selector).unwrap_or("null".to_string()),
-            serde_json::to_string(&script).unwrap_or("null".to_string())
+            serde_json::to_string(&script).unwrap_or("null".to_string()),
+            serde_json::to_string(&return_script).unwrap_or("false".to_string()),
+            serde_json::to_string(&wait_predicate).unwrap_or("null".to_string()),
+            serde_json::to_string(&network_idle_ms).unwrap_or("null".to_string())
         ))
         .with_ipc_handler(ipc_handler)
         .build()
@@ -190,10 +833,12 @@ fn setup_render(
     let state = RenderState {
         window,
         webview: Arc::new(Mutex::new(webview)),
-        html_result,
+        outcome,
         result_tx,
         start_time: std::time::Instant::now(),
         timeout_duration: Duration::from_millis(timeout_ms as u64),
+        on_event,
+        pending_events,
     };
 
     // Store the state in a thread-local map
@@ -209,86 +854,107 @@ thread_local! {
     static RENDER_STATES: RefCell<HashMap<WindowId, RenderState>> = RefCell::new(HashMap::new());
 }
 
-fn run_event_loop(event_loop: &mut EventLoop<()>, _target_window_id: WindowId) {
-    event_loop.run_return(|event, _, control_flow| {
-        *control_flow = ControlFlow::Poll;
-
-        // Check all active renders
-        let mut completed_windows = Vec::new();
-        let mut should_exit = false;
-
-        RENDER_STATES.with(|states| {
-            let mut states_map = states.borrow_mut();
-
-            // Process events for each window
-            for (window_id, state) in states_map.iter_mut() {
-                // Check if we have a result
-                if state.html_result.lock().unwrap().is_some() {
-                    let result = state
-                        .html_result
-                        .lock()
-                        .unwrap()
-                        .take()
-                        .ok_or(RenderError::Unknown("No HTML captured".to_string()));
-                    let _ = state.result_tx.send(result);
-                    completed_windows.push(*window_id);
-                    continue;
-                }
+// How often the event loop wakes up to check on active renders. Using a short
+// `WaitUntil` tick instead of `ControlFlow::Poll` keeps the owning thread mostly
+// asleep between ticks rather than busy-spinning, which matters once that thread
+// is Node's own main thread (see `render_page_async`).
+const EVENT_LOOP_TICK: Duration = Duration::from_millis(8);
 
-                // Check timeout
-                if state.start_time.elapsed() > state.timeout_duration {
-                    let _ = state.result_tx.send(Err(RenderError::Timeout));
-                    completed_windows.push(*window_id);
-                    continue;
-                }
+/// Processes one tao `Event` for `window_ids` (relaying queued lifecycle events, checking
+/// outcomes/timeouts/close requests, and poking `checkAndExtract` on `MainEventsCleared`),
+/// removing any of them that finished. Only touches `window_ids`, not every render in
+/// `RENDER_STATES` -- `RENDER_STATES` is shared process-wide, so a caller waiting on its
+/// own render(s) must not have its exit condition depend on unrelated renders elsewhere
+/// (e.g. a lingering `render_page_async` call) finishing too. Returns whether this event
+/// was `MainEventsCleared` and whether every one of `window_ids` is now done.
+fn process_render_states_event(event: &Event<()>, window_ids: &[WindowId]) -> (bool, bool) {
+    let saw_main_events_cleared = matches!(event, Event::MainEventsCleared);
+    let mut completed_windows = Vec::new();
 
-                // Process window events
-                if let Event::WindowEvent {
-                    window_id: event_window_id,
-                    event: window_event,
-                    ..
-                } = &event
-                {
-                    if event_window_id == window_id {
-                        match window_event {
-                            WindowEvent::CloseRequested => {
-                                let result = state
-                                    .html_result
-                                    .lock()
-                                    .unwrap()
-                                    .take()
-                                    .ok_or(RenderError::Unknown(
-                                        "Window closed before HTML captured".to_string(),
-                                    ));
-                                let _ = state.result_tx.send(result);
-                                completed_windows.push(*window_id);
-                            }
-                            _ => {}
-                        }
-                    }
-                }
+    RENDER_STATES.with(|states| {
+        let mut states_map = states.borrow_mut();
 
-                // On MainEventsCleared, trigger checkAndExtract
-                if matches!(event, Event::MainEventsCleared) {
-                    if let Ok(webview) = state.webview.lock() {
-                        let _ = webview
-                            .evaluate_script("window.checkAndExtract && window.checkAndExtract()");
-                    }
+        for &window_id in window_ids {
+            let Some(state) = states_map.get_mut(&window_id) else {
+                continue;
+            };
+
+            // Relay any lifecycle events queued since the last tick, in order.
+            if let Some(on_event) = &state.on_event {
+                for event_json in state.pending_events.lock().unwrap().drain(..) {
+                    on_event.call(event_json, ThreadsafeFunctionCallMode::NonBlocking);
                 }
             }
 
-            // Remove completed windows
-            for window_id in completed_windows {
-                states_map.remove(&window_id);
+            if let Some(result) = state.outcome.lock().unwrap().take() {
+                let _ = state.result_tx.send(result);
+                completed_windows.push(window_id);
+                continue;
+            }
+
+            if state.start_time.elapsed() > state.timeout_duration {
+                let _ = state.result_tx.send(Err(RenderError::Timeout));
+                completed_windows.push(window_id);
+                continue;
             }
 
-            // If no more active renders, exit the event loop
-            if states_map.is_empty() {
-                should_exit = true;
+            if let Event::WindowEvent {
+                window_id: event_window_id,
+                event: WindowEvent::CloseRequested,
+                ..
+            } = event
+            {
+                if *event_window_id == window_id {
+                    let result = state.outcome.lock().unwrap().take().unwrap_or_else(|| {
+                        Err(RenderError::Unknown(
+                            "Window closed before result captured".to_string(),
+                        ))
+                    });
+                    let _ = state.result_tx.send(result);
+                    completed_windows.push(window_id);
+                }
             }
-        });
 
-        if should_exit {
+            if saw_main_events_cleared {
+                if let Ok(webview) = state.webview.lock() {
+                    let _ = webview
+                        .evaluate_script("window.checkAndExtract && window.checkAndExtract()");
+                }
+            }
+        }
+
+        for window_id in completed_windows {
+            states_map.remove(&window_id);
+        }
+
+        let all_done = window_ids.iter().all(|id| !states_map.contains_key(id));
+        (saw_main_events_cleared, all_done)
+    })
+}
+
+fn run_event_loop(event_loop: &mut EventLoop<()>, window_ids: &[WindowId]) {
+    event_loop.run_return(|event, _, control_flow| {
+        *control_flow = ControlFlow::WaitUntil(std::time::Instant::now() + EVENT_LOOP_TICK);
+
+        let (_, all_done) = process_render_states_event(&event, window_ids);
+        if all_done {
+            *control_flow = ControlFlow::Exit;
+        }
+    });
+}
+
+/// Runs the main-thread event loop for one short `WaitUntil` burst — just long enough to
+/// see a `MainEventsCleared` tick — then returns, instead of looping until every render
+/// finishes. This is what lets `render_page_async` pump `EVENT_LOOP` cooperatively
+/// between ticks rather than blocking the thread that owns it. `window_ids` is the
+/// shared pump's current pending set (see `spawn_async_pump`), not necessarily every
+/// render in `RENDER_STATES`.
+fn pump_event_loop_once(event_loop: &mut EventLoop<()>, window_ids: &[WindowId]) {
+    event_loop.run_return(|event, _, control_flow| {
+        *control_flow = ControlFlow::WaitUntil(std::time::Instant::now() + EVENT_LOOP_TICK);
+
+        let (saw_tick, all_done) = process_render_states_event(&event, window_ids);
+        if saw_tick || all_done {
             *control_flow = ControlFlow::Exit;
         }
     });
@@ -305,4 +971,73 @@ mod tests {
         // Placeholder for now
         assert!(true);
     }
+
+    #[test]
+    fn origin_of_parses_scheme_host_and_port() {
+        assert_eq!(
+            origin_of("https://example.com/page?x=1#frag"),
+            Some("https://example.com".to_string())
+        );
+        assert_eq!(
+            origin_of("http://localhost:3000/"),
+            Some("http://localhost:3000".to_string())
+        );
+    }
+
+    #[test]
+    fn origin_of_rejects_urls_without_a_scheme() {
+        assert_eq!(origin_of("not-a-url"), None);
+    }
+
+    #[test]
+    fn origin_allowed_matches_only_the_tracked_origin() {
+        let allowed = vec!["https://example.com".to_string()];
+        assert!(origin_allowed(&allowed, "https://example.com"));
+        assert!(!origin_allowed(&allowed, "https://attacker.example"));
+    }
+
+    #[test]
+    fn origin_allowed_rejects_lookalike_origin_sharing_a_prefix() {
+        // Matching is exact: an origin that merely starts with an allowed one is rejected.
+        let allowed = vec!["https://example.com".to_string()];
+        assert!(!origin_allowed(&allowed, "https://example.com.attacker.example"));
+    }
+
+    #[test]
+    fn ipc_frame_deserializes_result_and_error_shapes() {
+        let result: IpcFrame =
+            serde_json::from_str(r#"{"type":"result","payload":{"html":"<p>hi</p>"}}"#).unwrap();
+        assert_eq!(result.frame_type, "result");
+        assert_eq!(result.payload.get("html").unwrap(), "<p>hi</p>");
+
+        let error: IpcFrame =
+            serde_json::from_str(r#"{"type":"error","payload":{"message":"boom"}}"#).unwrap();
+        assert_eq!(error.frame_type, "error");
+        assert_eq!(error.payload.get("message").unwrap(), "boom");
+    }
+
+    #[test]
+    fn build_relay_event_merges_object_payload_fields() {
+        let event = build_relay_event(
+            "console",
+            serde_json::json!({ "level": "warn", "args": ["uh oh"] }),
+        );
+        assert_eq!(event.get("type").unwrap(), "console");
+        assert_eq!(event.get("level").unwrap(), "warn");
+        assert_eq!(event.get("args").unwrap(), &serde_json::json!(["uh oh"]));
+    }
+
+    #[test]
+    fn build_relay_event_falls_back_to_payload_key_for_non_object_values() {
+        let event = build_relay_event("custom", serde_json::json!("just a string"));
+        assert_eq!(event.get("type").unwrap(), "custom");
+        assert_eq!(event.get("payload").unwrap(), "just a string");
+    }
+
+    #[test]
+    fn build_relay_event_omits_payload_key_when_payload_is_null() {
+        let event = build_relay_event("heartbeat", serde_json::Value::Null);
+        assert_eq!(event.get("type").unwrap(), "heartbeat");
+        assert!(event.get("payload").is_none());
+    }
 }